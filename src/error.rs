@@ -16,6 +16,10 @@ pub enum Error {
     Json(#[from] serde_json::Error),
     #[error("no primary key found for table")]
     NoPrimaryKey,
+    #[error("job not found")]
+    JobNotFound,
+    #[error("bad request: {0}")]
+    BadRequest(String),
 }
 
 impl IntoResponse for Error {
@@ -23,6 +27,8 @@ impl IntoResponse for Error {
         error!("Error: {self}");
         let (status, body) = match self {
             Error::NoPrimaryKey => (StatusCode::BAD_REQUEST, self.to_string()),
+            Error::JobNotFound => (StatusCode::NOT_FOUND, self.to_string()),
+            Error::BadRequest(_) => (StatusCode::BAD_REQUEST, self.to_string()),
             _ => (
                 StatusCode::INTERNAL_SERVER_ERROR,
                 "Unexpected error".to_owned(),
@@ -1,5 +1,5 @@
 use axum::{
-    extract::{Query, State},
+    extract::{FromRef, Query, State},
     http::{HeaderName, Method},
     middleware,
     response::IntoResponse,
@@ -22,15 +22,31 @@ use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
 mod auth;
 mod error;
+mod migrator;
+mod query;
+mod queue;
+mod realtime;
 
 use auth::authorize;
 use error::{Error, Result};
+use query::QueryBuilder;
+use realtime::Realtime;
 
 const DEFAULT_TIMEOUT: u64 = 15000;
 // pg-escape does not seem to escape multi word identifiers properly
 const VALID_CONSTRAINTS: [&str; 3] = ["PRIMARY KEY", "NOT NULL", "UNIQUE"];
+// Postgres truncates identifiers to this many bytes (NAMEDATALEN - 1) even
+// when quoted, so a generated name longer than this silently collides with
+// whatever else truncates to the same prefix.
+const POSTGRES_IDENTIFIER_LIMIT: usize = 63;
 
-type ConnectionPool = Pool<PostgresConnectionManager<NoTls>>;
+pub(crate) type ConnectionPool = Pool<PostgresConnectionManager<NoTls>>;
+
+#[derive(Clone, FromRef)]
+struct AppState {
+    pool: ConnectionPool,
+    realtime: Realtime,
+}
 
 fn get_url() -> String {
     dbg!(std::env::var("DATABASE_URL").expect("No DATABASE_URL specified, unable to contact DB"))
@@ -46,7 +62,8 @@ async fn main() {
         .with(tracing_subscriber::fmt::layer())
         .init();
 
-    let manager = PostgresConnectionManager::new_from_stringlike(get_url(), NoTls).unwrap();
+    let url = get_url();
+    let manager = PostgresConnectionManager::new_from_stringlike(url.clone(), NoTls).unwrap();
     let pool = Pool::builder()
         .max_size(5)
         .min_idle(Some(0))
@@ -55,6 +72,8 @@ async fn main() {
         .await
         .unwrap();
 
+    let realtime = realtime::connect(&url).await.unwrap();
+
     let timeout = std::env::var("TIMEOUT").unwrap_or_default();
     let timeout = timeout.parse::<u64>().unwrap_or(DEFAULT_TIMEOUT);
     let timeout = Duration::from_millis(timeout);
@@ -83,6 +102,11 @@ async fn main() {
             "https://railway.com".parse().unwrap(),
         ]);
 
+    migrator::run(&pool).await.unwrap();
+    queue::spawn_worker(pool.clone());
+    queue::spawn_reaper(pool.clone());
+    queue::spawn_pruner(pool.clone());
+
     // build our application with a route
     let app = Router::new()
         .route("/directories", get(directories))
@@ -93,13 +117,15 @@ async fn main() {
         .route("/object", put(update_object))
         .route("/object", delete(delete_object))
         .route("/generate/dummy", post(generate_dummy))
+        .merge(queue::routes())
+        .merge(realtime::routes())
         .layer(middleware::from_fn(authorize))
         .layer(cors)
         .layer(TraceLayer::new_for_http())
         .layer(TimeoutLayer::new(timeout))
         .layer(RequestDecompressionLayer::new())
         .layer(CompressionLayer::new())
-        .with_state(pool);
+        .with_state(AppState { pool, realtime });
 
     let listener = TcpListener::bind("0.0.0.0:9009").await.unwrap();
     tracing::info!("listening on {}", listener.local_addr().unwrap());
@@ -134,6 +160,8 @@ struct CreateDirectoryProperty {
     default: Option<String>,
     #[serde(default)]
     constraint: Option<String>,
+    #[serde(default)]
+    enum_values: Option<Vec<String>>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -144,17 +172,57 @@ struct CreateDirectoryRequest {
     properties: Vec<CreateDirectoryProperty>,
 }
 
+/// Name for the enum type backing `directory.column`, unambiguous even
+/// when the two names themselves contain underscores (e.g. directory
+/// `"a_b"` + column `"c"` can't collide with directory `"a"` + column
+/// `"b_c"`, since each part is length-prefixed).
+fn enum_type_name(directory: &str, column: &str) -> Result<String> {
+    let name = format!(
+        "{}_{directory}_{}_{column}_enum",
+        directory.len(),
+        column.len()
+    );
+    if name.len() > POSTGRES_IDENTIFIER_LIMIT {
+        return Err(Error::BadRequest(format!(
+            "directory/column name too long for a generated enum type: {directory}.{column}"
+        )));
+    }
+    Ok(name)
+}
+
 async fn create_directory(
     State(pool): State<ConnectionPool>,
     Json(req): Json<CreateDirectoryRequest>,
 ) -> Result<impl IntoResponse> {
-    let properties = req.properties.into_iter().map(|p| {
-        let mut prop = format!(
-            "{} {}",
-            escape_identifier(&p.name),
-            escape_identifier(&p.ty)
-        );
+    let mut conn = pool.get().await?;
+    // The enum type and the table that uses it must either both exist or
+    // neither do, so create both inside a single transaction.
+    let tx = conn.transaction().await?;
+
+    let mut properties = Vec::with_capacity(req.properties.len());
+    for p in req.properties {
+        let ty = match &p.enum_values {
+            Some(values) if !values.is_empty() => {
+                let enum_name = enum_type_name(&req.directory, &p.name)?;
+                let variants = values
+                    .iter()
+                    .map(|value| escape_literal(value))
+                    .collect::<Vec<String>>()
+                    .join(", ");
+                tx.batch_execute(&format!(
+                    "CREATE TYPE {} AS ENUM ({variants})",
+                    escape_identifier(&enum_name)
+                ))
+                .await?;
+                enum_name
+            }
+            _ => p.ty,
+        };
+
+        let mut prop = format!("{} {}", escape_identifier(&p.name), escape_identifier(&ty));
         if let Some(default) = &p.default {
+            // DDL can't bind parameters, so the default literal stays escaped
+            // rather than going through `QueryBuilder` like row values do.
             prop = format!("{prop} DEFAULT {}", escape_literal(default));
         }
 
@@ -165,19 +233,18 @@ async fn create_directory(
             }
         }
 
-        prop
-    });
+        properties.push(prop);
+    }
 
     let query = format!(
         "CREATE TABLE {} ({})",
         escape_identifier(&req.directory),
-        properties.collect::<Vec<String>>().join(", ")
+        properties.join(", ")
     );
 
-    let conn = pool.get().await?;
-
     // TODO: return created table
-    let _rows = conn.query(&query, &[]).await?;
+    tx.query(&query, &[]).await?;
+    tx.commit().await?;
     Ok(())
 }
 
@@ -198,12 +265,47 @@ async fn delete_directory(
     Ok(())
 }
 
+// Tables with no primary key can't be keyset-paginated (there's nothing
+// stable to seek on), so `cursor` there is just an OFFSET, parsed out of
+// the same opaque string the client echoes back.
+const PAGE_SIZE: i64 = 10;
+
+// Kept narrow on purpose: these go straight into the SQL template, so only
+// whitelisted operators are allowed through.
+const VALID_OPERATORS: [&str; 5] = ["=", "<>", "<", ">", "ILIKE"];
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Filter {
+    column: String,
+    operator: String,
+    value: String,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 struct ObjectsRequest {
     directory: String,
     #[serde(default)]
-    cursor: Option<i64>,
+    cursor: Option<String>,
+    // Query strings can't express nested structures, so filters travel as
+    // a single JSON-encoded `Vec<Filter>`.
+    #[serde(default)]
+    filters: Option<String>,
+    #[serde(default)]
+    order_by: Option<String>,
+    #[serde(default)]
+    order_direction: Option<String>,
+    #[serde(default)]
+    search: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ColumnMetadata {
+    name: String,
+    #[serde(default)]
+    enum_values: Option<Vec<String>>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -211,33 +313,206 @@ struct ObjectsRequest {
 struct ObjectsResponse {
     objects: Vec<Option<serde_json::Value>>,
     property_names: Vec<String>,
+    columns: Vec<ColumnMetadata>,
     primary_key: Option<String>,
     count: i64,
+    next_cursor: Option<String>,
+    has_more: bool,
 }
 
 async fn objects(
     State(pool): State<ConnectionPool>,
     Query(req): Query<ObjectsRequest>,
 ) -> Result<Json<ObjectsResponse>> {
-    let query = format!(
-        "SELECT column_name, data_type FROM information_schema.columns where table_name = {}",
-        escape_literal(&req.directory),
-    );
-
     let conn = pool.get().await?;
-    let rows = conn.query(&query, &[]).await?;
+    let rows = conn
+        .query(
+            "SELECT column_name, data_type, udt_name FROM information_schema.columns where table_name = $1",
+            &[&req.directory],
+        )
+        .await?;
 
     let mut properties: Vec<(String, String)> = Vec::with_capacity(rows.len());
+    let mut columns = Vec::with_capacity(rows.len());
     for row in rows {
-        properties.push((row.try_get(0)?, row.try_get(1)?));
+        let name: String = row.try_get(0)?;
+        let data_type: String = row.try_get(1)?;
+        let udt_name: String = row.try_get(2)?;
+
+        let enum_values = if data_type == "USER-DEFINED" {
+            let rows = conn
+                .query(
+                    "SELECT enumlabel FROM pg_enum
+                     JOIN pg_type ON pg_enum.enumtypid = pg_type.oid
+                     WHERE typname = $1
+                     ORDER BY enumsortorder",
+                    &[&udt_name],
+                )
+                .await?;
+            let values = rows
+                .into_iter()
+                .map(|row| row.try_get(0))
+                .collect::<std::result::Result<Vec<String>, _>>()?;
+            (!values.is_empty()).then_some(values)
+        } else {
+            None
+        };
+
+        columns.push(ColumnMetadata {
+            name: name.clone(),
+            enum_values,
+        });
+        properties.push((name, data_type));
     }
 
-    let query = format!(
-        "SELECT row_to_json({0}.*) FROM {0} LIMIT 10 OFFSET {1}",
-        escape_identifier(&req.directory),
-        req.cursor.unwrap_or(0),
-    );
-    let rows = conn.query(&query, &[]).await?;
+    let query = "SELECT pg_attribute.attname
+                 FROM pg_index, pg_class, pg_attribute, pg_namespace
+                 WHERE indrelid = pg_class.oid AND nspname = 'public' AND pg_class.relnamespace = pg_namespace.oid AND
+                   pg_attribute.attrelid = pg_class.oid AND pg_attribute.attnum = any(pg_index.indkey) AND indisprimary AND
+                   relName = $1";
+    let row = conn.query_opt(query, &[&req.directory]).await?;
+    let primary_key: Option<String> = row.map(|r| r.try_get(0)).transpose()?;
+
+    let filters: Vec<Filter> = match req.filters.as_deref() {
+        Some(filters) => serde_json::from_str(filters)
+            .map_err(|error| Error::BadRequest(format!("invalid filters: {error}")))?,
+        None => Vec::new(),
+    };
+
+    let mut builder = QueryBuilder::new();
+    let mut conditions = Vec::new();
+    for filter in &filters {
+        if !properties.iter().any(|(name, _)| name == &filter.column) {
+            return Err(Error::BadRequest(format!("unknown column: {}", filter.column)));
+        }
+        if !VALID_OPERATORS.contains(&filter.operator.as_str()) {
+            return Err(Error::BadRequest(format!(
+                "unsupported operator: {}",
+                filter.operator
+            )));
+        }
+        // Cast to text so the bound `String` value matches Postgres's type
+        // inference regardless of the column's real type. Comparisons with
+        // `<`/`>` are then lexicographic rather than numeric for non-text
+        // columns; good enough for a whitelist-driven filter, not a general
+        // query engine.
+        conditions.push(format!(
+            "{}::text {} {}",
+            escape_identifier(&filter.column),
+            filter.operator,
+            builder.push_param(&filter.value)
+        ));
+    }
+
+    let search_pattern = req.search.as_ref().map(|search| {
+        let escaped = search
+            .replace('\\', "\\\\")
+            .replace('%', "\\%")
+            .replace('_', "\\_");
+        format!("%{escaped}%")
+    });
+    if let Some(search_pattern) = &search_pattern {
+        let text_columns: Vec<&String> = properties
+            .iter()
+            .filter(|(_, ty)| ty == "text" || ty == "character varying")
+            .map(|(name, _)| name)
+            .collect();
+        if !text_columns.is_empty() {
+            let placeholder = builder.push_param(search_pattern);
+            let search_conditions = text_columns
+                .iter()
+                .map(|name| format!("{} ILIKE {placeholder}", escape_identifier(name)))
+                .collect::<Vec<String>>()
+                .join(" OR ");
+            conditions.push(format!("({search_conditions})"));
+        }
+    }
+
+    let order_by = match &req.order_by {
+        Some(order_by) => {
+            if !properties.iter().any(|(name, _)| name == order_by) {
+                return Err(Error::BadRequest(format!("unknown column: {order_by}")));
+            }
+            let direction = match req.order_direction.as_deref() {
+                Some("desc") | Some("DESC") => "DESC",
+                _ => "ASC",
+            };
+            Some(format!("{} {direction}", escape_identifier(order_by)))
+        }
+        None => None,
+    };
+
+    let count_query = {
+        let mut count_query =
+            format!("SELECT COUNT(*) FROM {}", escape_identifier(&req.directory));
+        if !conditions.is_empty() {
+            count_query.push_str(&format!(" WHERE {}", conditions.join(" AND ")));
+        }
+        count_query
+    };
+    let row = conn.query_opt(&count_query, builder.params()).await?;
+    let count: i64 = row.map_or(Ok(0), |r| r.try_get(0))?;
+
+    let mut rows = if let Some(primary_key) = &primary_key {
+        let mut conditions = conditions.clone();
+        if let Some(cursor) = &req.cursor {
+            // Same reasoning as the filter conditions above: cast to text so
+            // the bound `String` cursor matches Postgres's inferred
+            // parameter type regardless of the primary key's real type.
+            conditions.push(format!(
+                "{}::text > {}",
+                escape_identifier(primary_key),
+                builder.push_param(cursor)
+            ));
+        }
+
+        let mut query = format!(
+            "SELECT row_to_json({0}.*) FROM {0}",
+            escape_identifier(&req.directory)
+        );
+        if !conditions.is_empty() {
+            query.push_str(&format!(" WHERE {}", conditions.join(" AND ")));
+        }
+        // NOTE: a custom `orderBy` breaks keyset correctness, since the
+        // cursor comparison assumes primary-key order; still useful for
+        // one-shot browsing without deep paging.
+        query.push_str(&format!(
+            " ORDER BY {}",
+            order_by.clone().unwrap_or_else(|| {
+                // Match the `::text` cast on the cursor condition above: the
+                // seek compares lexicographically, so the default order has
+                // to sort the same way or pages overlap/skip rows for
+                // non-text primary keys (e.g. SERIAL ids).
+                format!("{}::text ASC", escape_identifier(primary_key))
+            })
+        ));
+        query.push_str(&format!(" LIMIT {}", PAGE_SIZE + 1));
+        conn.query(&query, builder.params()).await?
+    } else {
+        let offset = req
+            .cursor
+            .as_deref()
+            .and_then(|cursor| cursor.parse::<i64>().ok())
+            .unwrap_or(0);
+
+        let mut query = format!(
+            "SELECT row_to_json({0}.*) FROM {0}",
+            escape_identifier(&req.directory)
+        );
+        if !conditions.is_empty() {
+            query.push_str(&format!(" WHERE {}", conditions.join(" AND ")));
+        }
+        if let Some(order_by) = &order_by {
+            query.push_str(&format!(" ORDER BY {order_by}"));
+        }
+        query.push_str(&format!(" LIMIT {} OFFSET {offset}", PAGE_SIZE + 1));
+        conn.query(&query, builder.params()).await?
+    };
+
+    let has_more = rows.len() as i64 > PAGE_SIZE;
+    if has_more {
+        rows.truncate(PAGE_SIZE as usize);
+    }
 
     let mut objects = Vec::with_capacity(rows.len());
     let property_names = properties.iter().map(|(name, _)| name.to_owned()).collect();
@@ -258,23 +533,34 @@ async fn objects(
         objects.push(json);
     }
 
-    let query = format!("SELECT COUNT(*) FROM {}", escape_identifier(&req.directory));
-    let row = conn.query_opt(&query, &[]).await?;
-    let count: i64 = row.map_or(Ok(0), |r| r.try_get(0))?;
-
-    let query = "SELECT pg_attribute.attname
-                 FROM pg_index, pg_class, pg_attribute, pg_namespace
-                 WHERE indrelid = pg_class.oid AND nspname = 'public' AND pg_class.relnamespace = pg_namespace.oid AND
-                   pg_attribute.attrelid = pg_class.oid AND pg_attribute.attnum = any(pg_index.indkey) AND indisprimary AND
-                   relName = $1";
-    let row = conn.query_opt(query, &[&req.directory]).await?;
-    let primary_key: Option<String> = row.map(|r| r.try_get(0)).transpose()?;
+    let next_cursor = if !has_more {
+        None
+    } else if let Some(primary_key) = &primary_key {
+        objects
+            .last()
+            .and_then(|json| json.as_ref())
+            .and_then(|json| json.get(primary_key))
+            .map(|value| match value {
+                serde_json::Value::String(value) => value.to_owned(),
+                value => value.to_string(),
+            })
+    } else {
+        let offset = req
+            .cursor
+            .as_deref()
+            .and_then(|cursor| cursor.parse::<i64>().ok())
+            .unwrap_or(0);
+        Some((offset + PAGE_SIZE).to_string())
+    };
 
     Ok(Json(ObjectsResponse {
         objects,
         count,
         primary_key,
         property_names,
+        columns,
+        next_cursor,
+        has_more,
     }))
 }
 
@@ -289,27 +575,23 @@ async fn create_object(
     State(pool): State<ConnectionPool>,
     Query(req): Query<CreateObjectRequest>,
 ) -> Result<()> {
-    let names = req
-        .properties
-        .keys()
-        .map(|k| escape_identifier(k))
-        .collect::<Vec<String>>()
-        .join(", ");
-    // TODO: pass these as $n args and values as params to query
-    // let values = req.properties.values().enumerate().map(|(_, index)| format!("${index}")).collect::<Vec<String>>().join(", ");
-    let values = req
-        .properties
-        .values()
-        .map(|value| escape_literal(value))
-        .collect::<Vec<String>>()
-        .join(", ");
+    let mut builder = QueryBuilder::new();
+    let mut names = Vec::with_capacity(req.properties.len());
+    let mut placeholders = Vec::with_capacity(req.properties.len());
+    for (name, value) in &req.properties {
+        names.push(escape_identifier(name));
+        placeholders.push(builder.push_param(value));
+    }
+
     let query = dbg!(format!(
-        "INSERT INTO {} ({names}) VALUES ({values})",
-        escape_identifier(&req.directory)
+        "INSERT INTO {} ({}) VALUES ({})",
+        escape_identifier(&req.directory),
+        names.join(", "),
+        placeholders.join(", ")
     ));
 
     let conn = pool.get().await?;
-    let _rows = conn.query(&query, &[]).await?;
+    let _rows = conn.query(&query, builder.params()).await?;
 
     Ok(())
 }
@@ -338,20 +620,22 @@ async fn update_object(
         .ok_or(Error::NoPrimaryKey)?;
     let primary_key: String = row.try_get(0)?;
 
-    let values = req
+    let mut builder = QueryBuilder::new();
+    let assignments = req
         .properties
         .iter()
-        .map(|(key, value)| format!("{} = {}", escape_literal(key), escape_literal(value)))
+        .map(|(key, value)| format!("{} = {}", escape_identifier(key), builder.push_param(value)))
         .collect::<Vec<String>>()
         .join(", ");
+    let id_placeholder = builder.push_param(&req.id);
     let query = dbg!(format!(
-        "UPDATE {} SET {} WHERE {} = $1",
+        "UPDATE {} SET {} WHERE {} = {id_placeholder}",
         escape_identifier(&req.directory),
-        values,
+        assignments,
         escape_identifier(&primary_key)
     ));
 
-    let _rows = conn.query(&query, &[&req.id]).await?;
+    let _rows = conn.query(&query, builder.params()).await?;
 
     Ok(())
 }
@@ -391,6 +675,11 @@ async fn delete_object(
 }
 
 async fn generate_dummy(State(pool): State<ConnectionPool>) -> Result<()> {
+    generate_dummy_data(&pool).await
+}
+
+// Shared by the `/generate/dummy` route and `queue::Job::GenerateDummy`.
+pub(crate) async fn generate_dummy_data(pool: &ConnectionPool) -> Result<()> {
     let conn = pool.get().await?;
 
     let queries = [
@@ -405,13 +694,13 @@ async fn generate_dummy(State(pool): State<ConnectionPool>) -> Result<()> {
   setup varchar(255) NOT NULL,
   punchline varchar(500)
 );",
-        "INSERT INTO authors VALUES 
+        "INSERT INTO authors VALUES
 ('1','Thomas','Tank','thomas.the.tank@example.org'),
 ('2','Johnny','Coalheart','JCoal@example.com'),
 ('3','Brandy','Smokestack','smokestack@example.org'),
 ('4','Ima','Caboose','the.boose.is.loose@example.com'),
 ('5','Megan','Trainer','megan@example.com');",
-        "INSERT INTO jokes VALUES 
+        "INSERT INTO jokes VALUES
 ('1','I was gonna tell a joke','but I lost my train of thought'),
 ('2','How do trains eat?','They chew-chew'),
 ('3','Why did the crazy guy steal the train?','He had locomotives');",
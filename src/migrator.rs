@@ -0,0 +1,79 @@
+//! Embedded, versioned SQL migrations for the app's own metadata tables.
+//!
+//! Each migration runs at most once, tracked in `_data_ui_migrations`, and
+//! is wrapped in its own transaction so a failure rolls back cleanly
+//! instead of leaving the schema half-upgraded. The server refuses to
+//! serve if `run` errors out.
+//!
+//! `run` holds a Postgres advisory lock for its whole duration, so when
+//! several instances start at once (a normal rolling deploy) they apply
+//! migrations one at a time instead of racing each other's `CREATE`
+//! statements into duplicate-object errors.
+
+use crate::{error::Result, ConnectionPool};
+
+// Append new migrations here; never edit one that's already shipped.
+const MIGRATIONS: &[(i32, &str)] = &[
+    (1, include_str!("migrations/0001_job_queue.sql")),
+    (2, include_str!("migrations/0002_job_queue_done_status.sql")),
+    (3, include_str!("migrations/0003_job_queue_failed_status.sql")),
+    (4, include_str!("migrations/0004_job_queue_finished_at.sql")),
+];
+
+/// Arbitrary, app-specific key for the session-level advisory lock that
+/// serializes `run` across concurrently starting instances.
+const MIGRATION_LOCK_KEY: i64 = 0x64_61_74_61_5f_75_69;
+
+pub async fn run(pool: &ConnectionPool) -> Result<()> {
+    let conn = pool.get().await?;
+
+    conn.execute("SELECT pg_advisory_lock($1)", &[&MIGRATION_LOCK_KEY])
+        .await?;
+    let result = apply_migrations(&conn).await;
+    // A failed migration leaves this connection's transaction aborted, so
+    // the unlock itself can fail too; report the migration error, since
+    // that's the one that needs fixing, and let the lock clear when the
+    // connection is dropped.
+    if let Err(error) = conn
+        .execute("SELECT pg_advisory_unlock($1)", &[&MIGRATION_LOCK_KEY])
+        .await
+    {
+        tracing::warn!("failed to release migration advisory lock: {error}");
+    }
+
+    result
+}
+
+async fn apply_migrations(conn: &tokio_postgres::Client) -> Result<()> {
+    conn.batch_execute(
+        "CREATE TABLE IF NOT EXISTS _data_ui_migrations (
+             version INT PRIMARY KEY,
+             applied_at TIMESTAMP NOT NULL DEFAULT now()
+         );",
+    )
+    .await?;
+
+    for (version, sql) in MIGRATIONS {
+        let already_applied = conn
+            .query_opt(
+                "SELECT version FROM _data_ui_migrations WHERE version = $1",
+                &[version],
+            )
+            .await?
+            .is_some();
+        if already_applied {
+            continue;
+        }
+
+        tracing::info!("applying migration {version}");
+        conn.batch_execute(&format!(
+            "BEGIN;
+             {sql}
+             INSERT INTO _data_ui_migrations (version) VALUES ({version});
+             COMMIT;"
+        ))
+        .await?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,34 @@
+//! Helper for building parameterized SQL queries.
+//!
+//! Identifiers (table/column names) still have to be interpolated via
+//! `escape_identifier`, since Postgres has no way to bind those as
+//! parameters, but every user-supplied *value* should go through here
+//! instead of `escape_literal`, so it's bound positionally and Postgres
+//! can cache the query plan.
+
+use tokio_postgres::types::ToSql;
+
+/// Accumulates bound parameters for a query, handing back the `$n`
+/// placeholder for each one so callers can assemble the SQL template
+/// themselves and pass `params()` straight to `Client::query`.
+#[derive(Default)]
+pub struct QueryBuilder<'a> {
+    params: Vec<&'a (dyn ToSql + Sync)>,
+}
+
+impl<'a> QueryBuilder<'a> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Binds `value` as the next parameter and returns its placeholder,
+    /// e.g. `$1`, `$2`, ...
+    pub fn push_param(&mut self, value: &'a (dyn ToSql + Sync)) -> String {
+        self.params.push(value);
+        format!("${}", self.params.len())
+    }
+
+    pub fn params(&self) -> &[&'a (dyn ToSql + Sync)] {
+        &self.params
+    }
+}
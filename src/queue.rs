@@ -0,0 +1,227 @@
+//! Background job queue backed by a Postgres table.
+//!
+//! Jobs are enqueued as JSONB rows and claimed with `FOR UPDATE SKIP
+//! LOCKED` so the worker never races itself over the same row. A reaper
+//! resets jobs whose `heartbeat` has gone stale (worker crashed mid-job)
+//! back to `new` so they get picked up again instead of being stranded.
+//!
+//! Finished jobs are kept in the table with a terminal `done` or `failed`
+//! status, not deleted, so `GET /jobs/:id` can still report how a job
+//! ended to a caller polling for completion. A pruner later sweeps away
+//! finished jobs older than `RETENTION` so the table doesn't grow without
+//! bound.
+
+use axum::{
+    extract::{FromRef, Path, State},
+    routing::{get, post},
+    Json, Router,
+};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+use crate::{error::Error, error::Result, generate_dummy_data, ConnectionPool};
+
+/// How long the worker sleeps between polls when the queue is empty.
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+/// How often a running job refreshes its heartbeat.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
+/// How often the reaper looks for stale `running` jobs.
+const REAP_INTERVAL: Duration = Duration::from_secs(10);
+/// How often the pruner sweeps away old finished jobs.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(3600);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum Job {
+    GenerateDummy,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct JobStatusResponse {
+    id: String,
+    status: String,
+}
+
+pub fn routes<S>() -> Router<S>
+where
+    ConnectionPool: FromRef<S>,
+    S: Clone + Send + Sync + 'static,
+{
+    Router::new()
+        .route("/jobs", post(enqueue))
+        .route("/jobs/:id", get(status))
+}
+
+async fn enqueue(
+    State(pool): State<ConnectionPool>,
+    Json(job): Json<Job>,
+) -> Result<Json<JobStatusResponse>> {
+    let conn = pool.get().await?;
+    let job = serde_json::to_value(&job)?;
+    let row = conn
+        .query_one(
+            "INSERT INTO job_queue (queue, job) VALUES ('default', $1) RETURNING id::text, status::text",
+            &[&job],
+        )
+        .await?;
+    Ok(Json(JobStatusResponse {
+        id: row.try_get(0)?,
+        status: row.try_get(1)?,
+    }))
+}
+
+async fn status(
+    State(pool): State<ConnectionPool>,
+    Path(id): Path<String>,
+) -> Result<Json<JobStatusResponse>> {
+    let conn = pool.get().await?;
+    let row = conn
+        .query_opt(
+            "SELECT id::text, status::text FROM job_queue WHERE id = $1::uuid",
+            &[&id],
+        )
+        .await?
+        .ok_or(Error::JobNotFound)?;
+    Ok(Json(JobStatusResponse {
+        id: row.try_get(0)?,
+        status: row.try_get(1)?,
+    }))
+}
+
+/// Spawns the task that claims and runs queued jobs, one at a time.
+pub fn spawn_worker(pool: ConnectionPool) {
+    tokio::spawn(async move {
+        loop {
+            match claim_job(&pool).await {
+                Ok(Some((id, job))) => run_job(&pool, id, job).await,
+                Ok(None) => tokio::time::sleep(POLL_INTERVAL).await,
+                Err(error) => {
+                    tracing::error!("job worker error: {error}");
+                    tokio::time::sleep(POLL_INTERVAL).await;
+                }
+            }
+        }
+    });
+}
+
+/// Spawns the task that resets jobs abandoned by a crashed worker.
+pub fn spawn_reaper(pool: ConnectionPool) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(REAP_INTERVAL);
+        loop {
+            interval.tick().await;
+            if let Err(error) = reap_stale_jobs(&pool).await {
+                tracing::error!("job reaper error: {error}");
+            }
+        }
+    });
+}
+
+/// Spawns the task that prunes old finished jobs so the table doesn't
+/// grow without bound now that `done`/`failed` rows are kept.
+pub fn spawn_pruner(pool: ConnectionPool) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(PRUNE_INTERVAL);
+        loop {
+            interval.tick().await;
+            if let Err(error) = prune_finished_jobs(&pool).await {
+                tracing::error!("job pruner error: {error}");
+            }
+        }
+    });
+}
+
+async fn claim_job(pool: &ConnectionPool) -> Result<Option<(String, Job)>> {
+    let conn = pool.get().await?;
+    let row = conn
+        .query_opt(
+            "UPDATE job_queue SET status = 'running', heartbeat = now()
+             WHERE id = (
+                 SELECT id FROM job_queue WHERE status = 'new' ORDER BY id FOR UPDATE SKIP LOCKED LIMIT 1
+             )
+             RETURNING id::text, job",
+            &[],
+        )
+        .await?;
+    let Some(row) = row else {
+        return Ok(None);
+    };
+    let id: String = row.try_get(0)?;
+    let job: serde_json::Value = row.try_get(1)?;
+    Ok(Some((id, serde_json::from_value(job)?)))
+}
+
+async fn run_job(pool: &ConnectionPool, id: String, job: Job) {
+    let heartbeat_pool = pool.clone();
+    let heartbeat_id = id.clone();
+    let heartbeat = tokio::spawn(async move {
+        let mut interval = tokio::time::interval(HEARTBEAT_INTERVAL);
+        loop {
+            interval.tick().await;
+            if let Ok(conn) = heartbeat_pool.get().await {
+                let _ = conn
+                    .query(
+                        "UPDATE job_queue SET heartbeat = now() WHERE id = $1::uuid",
+                        &[&heartbeat_id],
+                    )
+                    .await;
+            }
+        }
+    });
+
+    let result = execute(pool, &job).await;
+    heartbeat.abort();
+
+    match result {
+        Ok(()) => {
+            if let Ok(conn) = pool.get().await {
+                let _ = conn
+                    .query(
+                        "UPDATE job_queue SET status = 'done', finished_at = now() WHERE id = $1::uuid",
+                        &[&id],
+                    )
+                    .await;
+            }
+        }
+        Err(error) => {
+            tracing::error!("job {id} failed: {error}");
+            if let Ok(conn) = pool.get().await {
+                let _ = conn
+                    .query(
+                        "UPDATE job_queue SET status = 'failed', finished_at = now() WHERE id = $1::uuid",
+                        &[&id],
+                    )
+                    .await;
+            }
+        }
+    }
+}
+
+async fn execute(pool: &ConnectionPool, job: &Job) -> Result<()> {
+    match job {
+        Job::GenerateDummy => generate_dummy_data(pool).await,
+    }
+}
+
+async fn reap_stale_jobs(pool: &ConnectionPool) -> Result<()> {
+    let conn = pool.get().await?;
+    conn.query(
+        "UPDATE job_queue SET status = 'new', heartbeat = NULL
+         WHERE status = 'running' AND heartbeat < now() - interval '30 seconds'",
+        &[],
+    )
+    .await?;
+    Ok(())
+}
+
+async fn prune_finished_jobs(pool: &ConnectionPool) -> Result<()> {
+    let conn = pool.get().await?;
+    conn.query(
+        "DELETE FROM job_queue
+         WHERE status IN ('done', 'failed') AND finished_at < now() - interval '24 hours'",
+        &[],
+    )
+    .await?;
+    Ok(())
+}
@@ -0,0 +1,251 @@
+//! Live row-change notifications over Server-Sent Events.
+//!
+//! The first subscription to a table installs a trigger that forwards row
+//! changes to `pg_notify`. A single dedicated connection `LISTEN`s on every
+//! subscribed table's channel and fans notifications out to subscribed SSE
+//! streams through a per-table `tokio::sync::broadcast` channel. If that
+//! connection drops, a supervisor task reconnects it and re-`LISTEN`s on
+//! every table still tracked; a periodic sweep `UNLISTEN`s and forgets
+//! tables whose last subscriber has disconnected.
+
+use axum::{
+    extract::{FromRef, Query, State},
+    response::sse::{Event, Sse},
+    routing::get,
+    Router,
+};
+use futures::stream::Stream;
+use postgres_protocol::escape::{escape_identifier, escape_literal};
+use serde::Deserialize;
+use std::{collections::HashMap, convert::Infallible, future::poll_fn, sync::Arc, time::Duration};
+use tokio::sync::{broadcast, Mutex, RwLock};
+use tokio_postgres::{AsyncMessage, NoTls};
+use tokio_stream::{wrappers::BroadcastStream, StreamExt};
+
+use crate::{error::Result, ConnectionPool};
+
+const CHANNEL_PREFIX: &str = "data_ui_";
+const BROADCAST_CAPACITY: usize = 100;
+/// How long to wait before retrying after the `LISTEN` connection drops.
+const RECONNECT_BACKOFF: Duration = Duration::from_secs(2);
+/// How often to drop channels whose last subscriber has disconnected.
+const IDLE_SWEEP_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Registry of per-table broadcast channels, fed by the dedicated
+/// `LISTEN` connection and subscribed to by the SSE handler.
+#[derive(Clone)]
+pub struct Realtime {
+    client: Arc<RwLock<tokio_postgres::Client>>,
+    channels: Arc<Mutex<HashMap<String, broadcast::Sender<String>>>>,
+}
+
+impl Realtime {
+    /// Ensures `table`'s channel is being `LISTEN`ed on, then subscribes.
+    ///
+    /// Holds `channels` locked across the `LISTEN` call itself (not just
+    /// the map update), so this can't interleave with `evict_idle_channels`
+    /// `UNLISTEN`ing the same table out from under a brand new subscriber.
+    async fn subscribe(&self, table: &str) -> Result<broadcast::Receiver<String>> {
+        let mut channels = self.channels.lock().await;
+        let sender = match channels.get(table) {
+            Some(sender) => sender.clone(),
+            None => {
+                let (sender, _) = broadcast::channel(BROADCAST_CAPACITY);
+                let channel = format!("{CHANNEL_PREFIX}{table}");
+                self.client
+                    .read()
+                    .await
+                    .batch_execute(&format!("LISTEN {}", escape_identifier(&channel)))
+                    .await?;
+                channels.insert(table.to_owned(), sender.clone());
+                sender
+            }
+        };
+
+        Ok(sender.subscribe())
+    }
+
+    async fn dispatch(&self, channel: &str, payload: String) {
+        let table = channel.trim_start_matches(CHANNEL_PREFIX);
+        if let Some(sender) = self.channels.lock().await.get(table) {
+            let _ = sender.send(payload);
+        }
+    }
+
+    /// Re-issues `LISTEN` for every table still tracked, after a reconnect.
+    /// Keeps going if one table's `LISTEN` fails instead of bailing out, so
+    /// one bad table can't strand every table after it in iteration order.
+    /// Holds `channels` locked for the whole pass, the same lock
+    /// `evict_idle_channels` holds for its sweep, so a table can't be
+    /// evicted mid-relisten and end up `LISTEN`ed on the new connection
+    /// with no entry left in the map.
+    async fn relisten_all(&self) {
+        let channels = self.channels.lock().await;
+        let client = self.client.read().await;
+        for table in channels.keys() {
+            let channel = format!("{CHANNEL_PREFIX}{table}");
+            if let Err(error) = client
+                .batch_execute(&format!("LISTEN {}", escape_identifier(&channel)))
+                .await
+            {
+                tracing::error!("failed to relisten on {channel}: {error}");
+            }
+        }
+    }
+
+    /// Drops channels with no subscribers left and stops listening on them,
+    /// so a long-running server doesn't accumulate one forever per table
+    /// ever subscribed to. Holds `channels` locked for the whole sweep, the
+    /// same lock `subscribe` holds across its own `LISTEN`, so a table
+    /// can't be re-subscribed to between being picked for eviction here and
+    /// its `UNLISTEN` actually running. The `UNLISTEN`s are batched into a
+    /// single round trip so that lock isn't held for one per idle table.
+    async fn evict_idle_channels(&self) {
+        let mut channels = self.channels.lock().await;
+        let idle: Vec<String> = channels
+            .iter()
+            .filter(|(_, sender)| sender.receiver_count() == 0)
+            .map(|(table, _)| table.clone())
+            .collect();
+        if idle.is_empty() {
+            return;
+        }
+        for table in &idle {
+            channels.remove(table);
+        }
+
+        let statements: String = idle
+            .iter()
+            .map(|table| {
+                format!(
+                    "UNLISTEN {};",
+                    escape_identifier(&format!("{CHANNEL_PREFIX}{table}"))
+                )
+            })
+            .collect();
+        if let Err(error) = self.client.read().await.batch_execute(&statements).await {
+            tracing::error!("failed to unlisten idle channels: {error}");
+        }
+    }
+}
+
+/// Opens the dedicated `LISTEN` connection and spawns the tasks that drive
+/// it: one fans notifications out through the registry and reconnects with
+/// backoff if the connection drops, the other periodically evicts channels
+/// nobody is subscribed to anymore.
+pub async fn connect(database_url: &str) -> Result<Realtime> {
+    let (client, connection) = tokio_postgres::connect(database_url, NoTls).await?;
+    let realtime = Realtime {
+        client: Arc::new(RwLock::new(client)),
+        channels: Arc::new(Mutex::new(HashMap::new())),
+    };
+
+    let listener = realtime.clone();
+    let database_url = database_url.to_owned();
+    tokio::spawn(async move {
+        let mut connection = connection;
+        loop {
+            loop {
+                match poll_fn(|cx| connection.poll_message(cx)).await {
+                    Some(Ok(AsyncMessage::Notification(notification))) => {
+                        listener
+                            .dispatch(notification.channel(), notification.payload().to_owned())
+                            .await;
+                    }
+                    Some(Ok(_)) => {}
+                    Some(Err(error)) => {
+                        tracing::error!("realtime listen connection error: {error}");
+                        break;
+                    }
+                    None => break,
+                }
+            }
+
+            loop {
+                tokio::time::sleep(RECONNECT_BACKOFF).await;
+                match tokio_postgres::connect(&database_url, NoTls).await {
+                    Ok((client, new_connection)) => {
+                        *listener.client.write().await = client;
+                        listener.relisten_all().await;
+                        connection = new_connection;
+                        break;
+                    }
+                    Err(error) => tracing::error!("realtime reconnect failed: {error}"),
+                }
+            }
+        }
+    });
+
+    let sweeper = realtime.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(IDLE_SWEEP_INTERVAL);
+        loop {
+            interval.tick().await;
+            sweeper.evict_idle_channels().await;
+        }
+    });
+
+    Ok(realtime)
+}
+
+pub fn routes<S>() -> Router<S>
+where
+    ConnectionPool: FromRef<S>,
+    Realtime: FromRef<S>,
+    S: Clone + Send + Sync + 'static,
+{
+    Router::new().route("/objects/subscribe", get(subscribe))
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SubscribeRequest {
+    directory: String,
+}
+
+async fn subscribe(
+    State(pool): State<ConnectionPool>,
+    State(realtime): State<Realtime>,
+    Query(req): Query<SubscribeRequest>,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>> {
+    ensure_trigger(&pool, &req.directory).await?;
+    let receiver = realtime.subscribe(&req.directory).await?;
+    let stream = BroadcastStream::new(receiver)
+        .filter_map(|message| message.ok().map(|payload| Ok(Event::default().data(payload))));
+    Ok(Sse::new(stream))
+}
+
+async fn ensure_trigger(pool: &ConnectionPool, table: &str) -> Result<()> {
+    let conn = pool.get().await?;
+    let channel = format!("{CHANNEL_PREFIX}{table}");
+    let function = format!("{table}_notify");
+    let trigger = format!("{table}_notify_trigger");
+
+    let channel_literal = escape_literal(&channel);
+    conn.batch_execute(&format!(
+        "CREATE OR REPLACE FUNCTION {}() RETURNS trigger AS $$
+         BEGIN
+             IF TG_OP = 'DELETE' THEN
+                 PERFORM pg_notify({channel_literal}, row_to_json(OLD)::text);
+                 RETURN OLD;
+             ELSE
+                 PERFORM pg_notify({channel_literal}, row_to_json(NEW)::text);
+                 RETURN NEW;
+             END IF;
+         END;
+         $$ LANGUAGE plpgsql;
+
+         DROP TRIGGER IF EXISTS {} ON {};
+         CREATE TRIGGER {}
+             AFTER INSERT OR UPDATE OR DELETE ON {}
+             FOR EACH ROW EXECUTE FUNCTION {}();",
+        escape_identifier(&function),
+        escape_identifier(&trigger),
+        escape_identifier(table),
+        escape_identifier(&trigger),
+        escape_identifier(table),
+        escape_identifier(&function),
+    ))
+    .await?;
+    Ok(())
+}